@@ -0,0 +1,119 @@
+//! Async mirror of `TransactionalConnection`, for callers built on
+//! `diesel_async` rather than a blocking `diesel::Connection`.
+//!
+//! Where `TransactionalConnection` serializes concurrent access behind a
+//! blocking `std::sync::Mutex`, this variant uses `tokio::sync::Mutex` so
+//! that contended access yields back to the executor instead of parking a
+//! thread -- the same serialize-via-async-mutex pattern the rest of the
+//! async ecosystem (axum/actix handlers, background-job workers) already
+//! relies on.
+
+use crate::{Error, ErrorVec};
+use diesel_async::{AsyncConnection, TransactionManager};
+use futures::future::BoxFuture;
+use tokio::sync::Mutex;
+
+#[cfg(all(nightly, feature = "rollback_hooks"))]
+pub type AsyncRollbackHook =
+    Box<dyn FnOnce() -> BoxFuture<'static, Result<(), failure::Error>> + Send>;
+#[cfg(all(not(nightly), feature = "rollback_hooks"))]
+pub type AsyncRollbackHook = Box<fn() -> BoxFuture<'static, Result<(), failure::Error>>>;
+
+/// Unlike `TransactionalConnection`, this type has no `Drop` impl: there is
+/// no blocking way to roll back a transaction from inside a synchronous
+/// destructor, so dropping one without calling `commit`/`rollback` just
+/// leaves the transaction open on the connection. `#[must_use]` is the best
+/// the compiler can do to flag that -- treat it as a hard requirement to
+/// always resolve one of these explicitly.
+#[must_use]
+#[cfg(feature = "rollback_hooks")]
+pub struct AsyncTransactionalConnection<C: AsyncConnection>(
+    Mutex<C>,
+    Mutex<Vec<AsyncRollbackHook>>,
+);
+#[must_use]
+#[cfg(not(feature = "rollback_hooks"))]
+pub struct AsyncTransactionalConnection<C: AsyncConnection>(Mutex<C>);
+
+impl<C: AsyncConnection> AsyncTransactionalConnection<C> {
+    pub async fn new(mut conn: C) -> Result<AsyncTransactionalConnection<C>, Error> {
+        C::TransactionManager::begin_transaction(&mut conn).await?;
+
+        #[cfg(feature = "rollback_hooks")]
+        let res = Ok(AsyncTransactionalConnection(
+            Mutex::new(conn),
+            Mutex::new(vec![]),
+        ));
+        #[cfg(not(feature = "rollback_hooks"))]
+        let res = Ok(AsyncTransactionalConnection(Mutex::new(conn)));
+
+        res
+    }
+
+    pub async fn rollback(self) -> Result<(), ErrorVec> {
+        let mut errs = vec![];
+
+        #[cfg(feature = "rollback_hooks")]
+        {
+            let mut guard = self.1.lock().await;
+            while !guard.is_empty() {
+                let hook = guard.pop().unwrap();
+                if let Err(e) = hook().await {
+                    errs.push(Error::from(e));
+                }
+            }
+        }
+
+        let mut guard = self.0.lock().await;
+        if let Err(e) = C::TransactionManager::rollback_transaction(&mut *guard).await {
+            errs.push(Error::from(e));
+        }
+
+        if !errs.is_empty() {
+            Err(errs)?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn commit(self) -> Result<(), Error> {
+        let mut guard = self.0.lock().await;
+        C::TransactionManager::commit_transaction(&mut *guard).await?;
+
+        Ok(())
+    }
+
+    /// Locks and hands back the wrapped connection for running queries
+    /// inside the open transaction, e.g.
+    /// `diesel::insert_into(..).execute(&mut *txconn.conn().await)`.
+    pub async fn conn(&self) -> tokio::sync::MutexGuard<'_, C> {
+        self.0.lock().await
+    }
+
+    pub async fn handle_result<T, E>(self, res: Result<T, E>) -> Result<T, failure::Error>
+    where
+        failure::Error: From<E>,
+    {
+        match &res {
+            Ok(_) => self.commit().await?,
+            Err(_) => self.rollback().await?,
+        };
+        Ok(res?)
+    }
+
+    #[cfg(all(nightly, feature = "rollback_hooks"))]
+    pub async fn add_rollback_hook(
+        &self,
+        hook: impl FnOnce() -> BoxFuture<'static, Result<(), failure::Error>> + Send + 'static,
+    ) {
+        self.1.lock().await.push(Box::new(hook));
+    }
+
+    #[cfg(all(not(nightly), feature = "rollback_hooks"))]
+    pub async fn add_rollback_hook(
+        &self,
+        hook: fn() -> BoxFuture<'static, Result<(), failure::Error>>,
+    ) {
+        self.1.lock().await.push(Box::new(hook));
+    }
+}
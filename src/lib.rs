@@ -4,10 +4,16 @@ extern crate failure;
 use diesel::connection::Connection;
 use diesel::connection::SimpleConnection;
 use diesel::connection::TransactionManager;
+use diesel::result::DatabaseErrorInformation;
 use diesel::result::Error as DieselError;
 use failure::Error as FailureError;
 use std::sync::Mutex;
 
+#[cfg(feature = "async")]
+mod async_connection;
+#[cfg(feature = "async")]
+pub use async_connection::{AsyncRollbackHook, AsyncTransactionalConnection};
+
 #[cfg(all(feature = "log_errors_on_drop", feature = "panic_errors_on_drop"))]
 compile_error!(
     "Features: \"log_errors_on_drop\" and \"panic_errors_on_drop\" are mutually exclusive!"
@@ -18,12 +24,19 @@ pub type RollbackHook = Box<dyn FnOnce() -> Result<(), failure::Error> + Send>;
 #[cfg(all(not(nightly), feature = "rollback_hooks"))]
 pub type RollbackHook = Box<fn() -> Result<(), failure::Error>>;
 
+#[cfg(all(nightly, feature = "commit_hooks"))]
+pub type CommitHook = Box<dyn FnOnce() -> Result<(), failure::Error> + Send>;
+#[cfg(all(not(nightly), feature = "commit_hooks"))]
+pub type CommitHook = Box<fn() -> Result<(), failure::Error>>;
+
 #[derive(Debug, Fail)]
 pub enum Error {
     #[fail(display = "Diesel Error: {}", _0)]
     Diesel(DieselError),
     #[fail(display = "Custom Error: {}", _0)]
     Failure(FailureError),
+    #[fail(display = "{}", _0)]
+    Multiple(ErrorVec),
 }
 impl From<DieselError> for Error {
     fn from(err: DieselError) -> Self {
@@ -35,6 +48,11 @@ impl From<FailureError> for Error {
         Error::Failure(err)
     }
 }
+impl From<ErrorVec> for Error {
+    fn from(err: ErrorVec) -> Self {
+        Error::Multiple(err)
+    }
+}
 
 #[derive(Debug, Fail)]
 #[fail(display = "The following errors ocurred: {:?}.", _0)]
@@ -45,32 +63,143 @@ impl From<Vec<Error>> for ErrorVec {
     }
 }
 
-#[cfg(feature = "rollback_hooks")]
-pub struct TransactionalConnection<T: Connection>(Mutex<T>, Mutex<Vec<RollbackHook>>);
-#[cfg(not(feature = "rollback_hooks"))]
-pub struct TransactionalConnection<T: Connection>(Mutex<T>);
-impl<C: Connection> TransactionalConnection<C> {
-    pub fn new(conn: C) -> Result<TransactionalConnection<C>, Error> {
+/// The `ISOLATION LEVEL` to request via `SET TRANSACTION` when opening a
+/// `TransactionalConnection` through `TransactionalConnection::builder`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IsolationLevel {
+    ReadUncommitted,
+    ReadCommitted,
+    RepeatableRead,
+    Serializable,
+}
+
+impl IsolationLevel {
+    fn as_sql(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadUncommitted => "READ UNCOMMITTED",
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::RepeatableRead => "REPEATABLE READ",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+/// Builds a `TransactionalConnection` with an explicit isolation level and
+/// access mode, emitting the corresponding `SET TRANSACTION` statement right
+/// after the underlying `begin_transaction` succeeds.
+pub struct TransactionalConnectionBuilder<C: Connection> {
+    conn: C,
+    isolation_level: Option<IsolationLevel>,
+    read_only: bool,
+    deferrable: bool,
+}
+
+impl<C: Connection> TransactionalConnectionBuilder<C> {
+    fn new(conn: C) -> Self {
+        TransactionalConnectionBuilder {
+            conn,
+            isolation_level: None,
+            read_only: false,
+            deferrable: false,
+        }
+    }
+
+    pub fn isolation_level(mut self, isolation_level: IsolationLevel) -> Self {
+        self.isolation_level = Some(isolation_level);
+        self
+    }
+
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Requests `DEFERRABLE`, which Postgres only honors for a
+    /// `SERIALIZABLE READ ONLY` transaction.
+    pub fn deferrable(mut self, deferrable: bool) -> Self {
+        self.deferrable = deferrable;
+        self
+    }
+
+    pub fn build(self) -> Result<TransactionalConnection<C>, Error> {
+        let conn = self.conn;
         let man = conn.transaction_manager();
 
         man.begin_transaction(&conn)?;
 
-        #[cfg(feature = "rollback_hooks")]
-        let res = Ok(TransactionalConnection(
-            Mutex::new(conn),
-            Mutex::new(vec![]),
-        ));
-        #[cfg(not(feature = "rollback_hooks"))]
-        let res = Ok(TransactionalConnection(Mutex::new(conn)));
+        if let Some(stmt) = set_transaction_statement(self.isolation_level, self.read_only, self.deferrable) {
+            if let Err(e) = conn.batch_execute(&stmt) {
+                let _ = man.rollback_transaction(&conn);
+                return Err(Error::from(e));
+            }
+        }
+
+        Ok(TransactionalConnection::from_parts(conn))
+    }
+}
+
+/// Builds the `SET TRANSACTION` statement for the given options, joining the
+/// requested clauses with commas per Postgres's `transaction_mode [, ...]`
+/// grammar. Returns `None` when nothing was requested.
+fn set_transaction_statement(
+    isolation_level: Option<IsolationLevel>,
+    read_only: bool,
+    deferrable: bool,
+) -> Option<String> {
+    if isolation_level.is_none() && !read_only && !deferrable {
+        return None;
+    }
+
+    let mut clauses = vec![];
+    if let Some(isolation_level) = isolation_level {
+        clauses.push(format!("ISOLATION LEVEL {}", isolation_level.as_sql()));
+    }
+    if read_only {
+        clauses.push(String::from("READ ONLY"));
+    }
+    if deferrable {
+        clauses.push(String::from("DEFERRABLE"));
+    }
+
+    Some(format!("SET TRANSACTION {}", clauses.join(", ")))
+}
+
+pub struct TransactionalConnection<T: Connection> {
+    conn: Mutex<T>,
+    #[cfg(feature = "rollback_hooks")]
+    rollback_hooks: Mutex<Vec<RollbackHook>>,
+    #[cfg(feature = "commit_hooks")]
+    commit_hooks: Mutex<Vec<CommitHook>>,
+}
+impl<C: Connection> TransactionalConnection<C> {
+    pub fn new(conn: C) -> Result<TransactionalConnection<C>, Error> {
+        Self::builder(conn).build()
+    }
+
+    /// Starts building a `TransactionalConnection` with explicit control over
+    /// the isolation level and access mode of the opened transaction, rather
+    /// than the plain `BEGIN` that `new` issues.
+    pub fn builder(conn: C) -> TransactionalConnectionBuilder<C> {
+        TransactionalConnectionBuilder::new(conn)
+    }
 
-        res
+    fn from_parts(conn: C) -> TransactionalConnection<C> {
+        TransactionalConnection {
+            conn: Mutex::new(conn),
+            #[cfg(feature = "rollback_hooks")]
+            rollback_hooks: Mutex::new(vec![]),
+            #[cfg(feature = "commit_hooks")]
+            commit_hooks: Mutex::new(vec![]),
+        }
     }
 
-    pub fn rollback(self) -> Result<(), ErrorVec> {
+    /// Rolls back the transaction, always handing back the underlying
+    /// connection -- alongside the errors, if any -- rather than dropping it.
+    pub fn rollback(self) -> Result<C, (C, ErrorVec)> {
         let mut errs = vec![];
         #[cfg(feature = "rollback_hooks")]
         {
-            let mut guard = self.1.lock().unwrap();
+            let mut guard = self.rollback_hooks.lock().unwrap();
             while !guard.is_empty() {
                 let hook = guard.pop().unwrap();
                 match hook() {
@@ -80,35 +209,93 @@ impl<C: Connection> TransactionalConnection<C> {
             }
         }
 
-        let guard = self.0.lock().unwrap();
-        let man = guard.transaction_manager();
+        {
+            let guard = self.conn.lock().unwrap();
+            let man = guard.transaction_manager();
+
+            while TransactionManager::<C>::get_transaction_depth(man) > 0 {
+                match man.rollback_transaction(&*guard) {
+                    Err(e) => {
+                        errs.push(Error::from(e));
+                        break;
+                    }
+                    _ => (),
+                }
+            }
+        }
 
-        while TransactionManager::<C>::get_transaction_depth(man) > 0 {
-            match man.rollback_transaction(&*guard) {
-                Err(e) => {
+        let conn = self.unwrap_connection();
+
+        if errs.is_empty() {
+            Ok(conn)
+        } else {
+            Err((conn, ErrorVec(errs)))
+        }
+    }
+
+    /// Commits the transaction, always handing back the underlying
+    /// connection -- alongside the errors, if any -- rather than dropping it.
+    ///
+    /// Commit hooks added via `add_commit_hook` run, in FIFO order, only
+    /// after the final `COMMIT` succeeds; they are never run on `rollback` or
+    /// `Drop`.
+    pub fn commit(self) -> Result<C, (C, ErrorVec)> {
+        let mut errs = vec![];
+
+        {
+            let guard = self.conn.lock().unwrap();
+            let man = guard.transaction_manager();
+
+            while TransactionManager::<C>::get_transaction_depth(man) > 0 {
+                if let Err(e) = man.commit_transaction(&*guard) {
                     errs.push(Error::from(e));
                     break;
                 }
-                _ => (),
             }
         }
 
-        if !errs.is_empty() {
-            Err(errs)?;
+        #[cfg(feature = "commit_hooks")]
+        if errs.is_empty() {
+            let mut guard = self.commit_hooks.lock().unwrap();
+            for hook in guard.drain(..) {
+                if let Err(e) = hook() {
+                    errs.push(Error::from(e));
+                }
+            }
         }
 
-        Ok(())
+        let conn = self.unwrap_connection();
+
+        if errs.is_empty() {
+            Ok(conn)
+        } else {
+            Err((conn, ErrorVec(errs)))
+        }
     }
 
-    pub fn commit(self) -> Result<(), Error> {
-        let guard = self.0.lock().unwrap();
-        let man = guard.transaction_manager();
+    /// Rolls back any open transaction and hands back the underlying
+    /// connection, e.g. so it can be returned to a pool after the caller is
+    /// done with it rather than dropped and re-established.
+    pub fn into_inner(self) -> C {
+        self.rollback()
+            .unwrap_or_else(|(_, errs)| panic!("failed to roll back transaction: {}", errs))
+    }
 
-        while TransactionManager::<C>::get_transaction_depth(man) > 0 {
-            man.commit_transaction(&*guard)?;
-        }
+    /// Pulls the connection out of `self` without running `Drop`, whose
+    /// rollback hooks and `ROLLBACK` must not run against a transaction
+    /// `rollback`/`commit` has already resolved.
+    fn unwrap_connection(self) -> C {
+        let this = std::mem::ManuallyDrop::new(self);
 
-        Ok(())
+        // SAFETY: `this` is never used again after these reads, and wrapping
+        // `self` in `ManuallyDrop` suppresses its `Drop` impl, so each field
+        // is read out of the original allocation exactly once.
+        #[cfg(feature = "rollback_hooks")]
+        drop(unsafe { std::ptr::read(&this.rollback_hooks) });
+        #[cfg(feature = "commit_hooks")]
+        drop(unsafe { std::ptr::read(&this.commit_hooks) });
+
+        unsafe { std::ptr::read(&this.conn) }.into_inner().unwrap()
     }
 
     pub fn handle_result<T, E>(self, res: Result<T, E>) -> Result<T, failure::Error>
@@ -116,20 +303,241 @@ impl<C: Connection> TransactionalConnection<C> {
         failure::Error: From<E>,
     {
         match &res {
-            Ok(_) => self.commit()?,
-            Err(_) => self.rollback()?,
+            Ok(_) => {
+                self.commit().map_err(|(_, errs)| errs)?;
+            }
+            Err(_) => {
+                self.rollback().map_err(|(_, errs)| errs)?;
+            }
         };
         Ok(res?)
     }
 
     #[cfg(all(nightly, feature = "rollback_hooks"))]
     pub fn add_rollback_hook(&self, hook: impl FnOnce() -> Result<(), failure::Error> + Send) {
-        self.1.lock().unwrap().push(Box::new(hook));
+        self.rollback_hooks.lock().unwrap().push(Box::new(hook));
     }
 
     #[cfg(all(not(nightly), feature = "rollback_hooks"))]
     pub fn add_rollback_hook(&self, hook: fn() -> Result<(), failure::Error>) {
-        self.1.lock().unwrap().push(Box::new(hook));
+        self.rollback_hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Registers a hook that runs only if `commit` succeeds, after the final
+    /// `COMMIT` -- the symmetric counterpart to `add_rollback_hook`, for
+    /// side-effecting IO (enqueuing a background job, publishing a
+    /// notification) that would be wrong to run on a rolled-back transaction.
+    #[cfg(all(nightly, feature = "commit_hooks"))]
+    pub fn add_commit_hook(&self, hook: impl FnOnce() -> Result<(), failure::Error> + Send) {
+        self.commit_hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    #[cfg(all(not(nightly), feature = "commit_hooks"))]
+    pub fn add_commit_hook(&self, hook: fn() -> Result<(), failure::Error>) {
+        self.commit_hooks.lock().unwrap().push(Box::new(hook));
+    }
+
+    /// Opens a savepoint nested inside the current transaction stack.
+    ///
+    /// Because the inner connection's `TransactionManager` already tracks a
+    /// depth > 0 by this point, its `begin_transaction` issues a
+    /// `SAVEPOINT` rather than a fresh `BEGIN`. The returned guard remembers
+    /// the depth it was opened at, so callers can speculatively attempt work
+    /// and discard just that slice via `rollback`/`Drop` without unwinding
+    /// the rest of the shared transaction.
+    pub fn savepoint(&self) -> Result<SavepointGuard<C>, Error> {
+        let guard = self.conn.lock().unwrap();
+        let man = guard.transaction_manager();
+
+        man.begin_transaction(&*guard)?;
+        let depth = TransactionManager::<C>::get_transaction_depth(man);
+
+        Ok(SavepointGuard {
+            conn: self,
+            depth,
+            finished: false,
+        })
+    }
+}
+
+/// A guard over a single savepoint opened by `TransactionalConnection::savepoint`.
+///
+/// Dropping the guard without calling `commit` rolls the savepoint back, the
+/// same way `TransactionalConnection` itself rolls back on `Drop`.
+pub struct SavepointGuard<'a, C: Connection> {
+    conn: &'a TransactionalConnection<C>,
+    depth: u32,
+    finished: bool,
+}
+
+impl<'a, C: Connection> SavepointGuard<'a, C> {
+    /// Releases the savepoint, keeping its work as part of the enclosing transaction.
+    ///
+    /// If another `SavepointGuard` opened after this one is still
+    /// outstanding, releasing this one would act on the wrong savepoint, so
+    /// this instead leaks `self` (skipping `Drop`'s rollback, which would
+    /// have the same problem) and returns an error; the outstanding guard's
+    /// `commit`/`rollback`/`Drop` is what actually needs to run first.
+    pub fn commit(mut self) -> Result<(), Error> {
+        let guard = self.conn.conn.lock().unwrap();
+        let man = guard.transaction_manager();
+
+        let depth = TransactionManager::<C>::get_transaction_depth(man);
+        if depth != self.depth {
+            let err = format_err!(
+                "savepoint committed out of order: expected depth {}, found {}",
+                self.depth,
+                depth
+            );
+            drop(guard);
+            std::mem::forget(self);
+            return Err(Error::from(err));
+        }
+
+        man.commit_transaction(&*guard)?;
+        self.finished = true;
+
+        Ok(())
+    }
+
+    /// Rolls back to the savepoint, undoing only the work done since it was opened.
+    ///
+    /// If another `SavepointGuard` opened after this one is still
+    /// outstanding, rolling back this one would act on the wrong savepoint,
+    /// so this instead leaks `self` (skipping `Drop`'s rollback, which would
+    /// have the same problem) and returns an error; the outstanding guard's
+    /// `commit`/`rollback`/`Drop` is what actually needs to run first.
+    pub fn rollback(mut self) -> Result<(), Error> {
+        let guard = self.conn.conn.lock().unwrap();
+        let man = guard.transaction_manager();
+
+        let depth = TransactionManager::<C>::get_transaction_depth(man);
+        if depth != self.depth {
+            let err = format_err!(
+                "savepoint rolled back out of order: expected depth {}, found {}",
+                self.depth,
+                depth
+            );
+            drop(guard);
+            std::mem::forget(self);
+            return Err(Error::from(err));
+        }
+
+        man.rollback_transaction(&*guard)?;
+        self.finished = true;
+
+        Ok(())
+    }
+}
+
+impl<'a, C: Connection> std::ops::Drop for SavepointGuard<'a, C> {
+    fn drop(&mut self) {
+        if self.finished {
+            return;
+        }
+
+        let guard = self.conn.conn.lock().unwrap();
+        let man = guard.transaction_manager();
+
+        let _res = man.rollback_transaction(&*guard);
+
+        #[cfg(feature = "log_errors_on_drop")]
+        _res.unwrap_or_else(|e| {
+            eprintln!(
+                "WARNING: Error ocurred while attempting savepoint rollback: {}",
+                e
+            );
+        });
+
+        #[cfg(feature = "panic_errors_on_drop")]
+        _res.unwrap_or_else(|e| panic!("{}", e));
+    }
+}
+
+/// Configuration for the retry behavior of `handle_result_retrying`.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    /// Base delay before retrying; doubled after each failed attempt (e.g.
+    /// `backoff`, then `backoff * 2`, then `backoff * 4`, ...).
+    pub backoff: Option<std::time::Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            backoff: None,
+        }
+    }
+}
+
+fn is_retryable_error(err: &DieselError) -> bool {
+    use diesel::result::DatabaseErrorKind;
+
+    match err {
+        // SQLSTATE 40001.
+        DieselError::DatabaseError(DatabaseErrorKind::SerializationFailure, _) => true,
+        // SQLSTATE 40P01. Diesel only special-cases a handful of codes into
+        // `DatabaseErrorKind`, so a deadlock falls through to `Unknown` and
+        // doesn't surface its SQLSTATE through `DatabaseErrorInformation` at
+        // all; the exact, locale-independent phrase Postgres's server emits
+        // for that state is the only signal the public API exposes.
+        DieselError::DatabaseError(DatabaseErrorKind::Unknown, info) => {
+            info.message().contains("deadlock detected")
+        }
+        _ => false,
+    }
+}
+
+/// Runs `body` inside a fresh `TransactionalConnection` built from
+/// `builder_factory`, retrying the whole attempt (new connection, new
+/// transaction, new call to `body`) when it fails with a Postgres
+/// serialization failure or deadlock.
+///
+/// `builder_factory` is called once per attempt, so it must return a
+/// `TransactionalConnectionBuilder` rather than a bare connection -- a
+/// `Serializable` isolation level, for instance, needs to be reapplied on
+/// every retry.
+pub fn handle_result_retrying<C, T>(
+    builder_factory: impl Fn() -> Result<TransactionalConnectionBuilder<C>, Error>,
+    policy: RetryPolicy,
+    mut body: impl FnMut(&TransactionalConnection<C>) -> Result<T, DieselError>,
+) -> Result<T, Error>
+where
+    C: Connection,
+{
+    let mut attempt = 1;
+
+    loop {
+        let txconn = builder_factory()?.build()?;
+
+        match body(&txconn) {
+            Ok(val) => match txconn.commit() {
+                Ok(_) => return Ok(val),
+                Err((_, errs)) => {
+                    let retryable = errs.0.iter().any(|e| match e {
+                        Error::Diesel(e) => is_retryable_error(e),
+                        _ => false,
+                    });
+                    if attempt >= policy.max_attempts || !retryable {
+                        return Err(Error::from(errs));
+                    }
+                }
+            },
+            Err(e) => {
+                let _ = txconn.rollback();
+
+                if attempt >= policy.max_attempts || !is_retryable_error(&e) {
+                    return Err(Error::from(e));
+                }
+            }
+        }
+
+        if let Some(backoff) = policy.backoff {
+            std::thread::sleep(backoff * 2u32.pow(attempt - 1));
+        }
+        attempt += 1;
     }
 }
 
@@ -137,7 +545,7 @@ impl<C: Connection> std::ops::Drop for TransactionalConnection<C> {
     fn drop(&mut self) {
         #[cfg(feature = "rollback_hooks")]
         {
-            let guard = self.1.lock().unwrap();
+            let guard = self.rollback_hooks.lock().unwrap();
             for hook in &*guard {
                 let _res = hook();
 
@@ -154,7 +562,7 @@ impl<C: Connection> std::ops::Drop for TransactionalConnection<C> {
             }
         }
 
-        let guard = self.0.lock().unwrap();
+        let guard = self.conn.lock().unwrap();
         let man = guard.transaction_manager();
 
         while TransactionManager::<C>::get_transaction_depth(man) > 0 {
@@ -176,7 +584,7 @@ impl<C: Connection> std::ops::Drop for TransactionalConnection<C> {
 
 impl<C: Connection> SimpleConnection for TransactionalConnection<C> {
     fn batch_execute(&self, query: &str) -> diesel::QueryResult<()> {
-        self.0.lock().unwrap().batch_execute(query)
+        self.conn.lock().unwrap().batch_execute(query)
     }
 }
 
@@ -191,7 +599,7 @@ impl<C: Connection> Connection for TransactionalConnection<C> {
     }
 
     fn execute(&self, query: &str) -> diesel::QueryResult<usize> {
-        self.0.lock().unwrap().execute(query)
+        self.conn.lock().unwrap().execute(query)
     }
 
     fn query_by_index<T, U>(&self, source: T) -> diesel::QueryResult<Vec<U>>
@@ -202,7 +610,7 @@ impl<C: Connection> Connection for TransactionalConnection<C> {
         Self::Backend: diesel::sql_types::HasSqlType<T::SqlType>,
         U: diesel::deserialize::Queryable<T::SqlType, Self::Backend>,
     {
-        self.0.lock().unwrap().query_by_index(source)
+        self.conn.lock().unwrap().query_by_index(source)
     }
 
     fn query_by_name<T, U>(&self, source: &T) -> diesel::QueryResult<Vec<U>>
@@ -210,14 +618,14 @@ impl<C: Connection> Connection for TransactionalConnection<C> {
         T: diesel::query_builder::QueryFragment<Self::Backend> + diesel::query_builder::QueryId,
         U: diesel::deserialize::QueryableByName<Self::Backend>,
     {
-        self.0.lock().unwrap().query_by_name(source)
+        self.conn.lock().unwrap().query_by_name(source)
     }
 
     fn execute_returning_count<T>(&self, source: &T) -> diesel::QueryResult<usize>
     where
         T: diesel::query_builder::QueryFragment<Self::Backend> + diesel::query_builder::QueryId,
     {
-        self.0.lock().unwrap().execute_returning_count(source)
+        self.conn.lock().unwrap().execute_returning_count(source)
     }
 
     fn transaction_manager(&self) -> &Self::TransactionManager {
@@ -227,23 +635,23 @@ impl<C: Connection> Connection for TransactionalConnection<C> {
 
 impl<C: Connection> TransactionManager<TransactionalConnection<C>> for TransactionalConnection<C> {
     fn begin_transaction(&self, conn: &TransactionalConnection<C>) -> diesel::QueryResult<()> {
-        let conn = conn.0.lock().unwrap();
+        let conn = conn.conn.lock().unwrap();
         conn.transaction_manager().begin_transaction(&*conn)
     }
 
     fn rollback_transaction(&self, conn: &TransactionalConnection<C>) -> diesel::QueryResult<()> {
-        let conn = conn.0.lock().unwrap();
+        let conn = conn.conn.lock().unwrap();
         conn.transaction_manager().rollback_transaction(&*conn)
     }
 
     fn commit_transaction(&self, conn: &TransactionalConnection<C>) -> diesel::QueryResult<()> {
-        let conn = conn.0.lock().unwrap();
+        let conn = conn.conn.lock().unwrap();
         conn.transaction_manager().commit_transaction(&*conn)
     }
 
     fn get_transaction_depth(&self) -> u32 {
         diesel::connection::TransactionManager::<C>::get_transaction_depth(
-            &*self.0.lock().unwrap().transaction_manager(),
+            &*self.conn.lock().unwrap().transaction_manager(),
         )
     }
 }
@@ -284,3 +692,64 @@ fn multiple_threads() {
             .handle_result(res)
     );
 }
+
+#[test]
+fn set_transaction_statement_joins_clauses_with_commas() {
+    assert_eq!(set_transaction_statement(None, false, false), None);
+    assert_eq!(
+        set_transaction_statement(Some(IsolationLevel::Serializable), false, false),
+        Some("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE".to_string())
+    );
+    assert_eq!(
+        set_transaction_statement(Some(IsolationLevel::Serializable), true, true),
+        Some("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE, READ ONLY, DEFERRABLE".to_string())
+    );
+    assert_eq!(
+        set_transaction_statement(None, true, false),
+        Some("SET TRANSACTION READ ONLY".to_string())
+    );
+}
+
+#[test]
+fn is_retryable_error_matches_serialization_failure_and_deadlock() {
+    use diesel::result::DatabaseErrorKind;
+
+    struct FakeInfo(&'static str);
+    impl DatabaseErrorInformation for FakeInfo {
+        fn message(&self) -> &str {
+            self.0
+        }
+        fn details(&self) -> Option<&str> {
+            None
+        }
+        fn hint(&self) -> Option<&str> {
+            None
+        }
+        fn table_name(&self) -> Option<&str> {
+            None
+        }
+        fn column_name(&self) -> Option<&str> {
+            None
+        }
+        fn constraint_name(&self) -> Option<&str> {
+            None
+        }
+        fn statement_position(&self) -> Option<i32> {
+            None
+        }
+    }
+
+    assert!(is_retryable_error(&DieselError::DatabaseError(
+        DatabaseErrorKind::SerializationFailure,
+        Box::new(FakeInfo("serialization_failure"))
+    )));
+    assert!(is_retryable_error(&DieselError::DatabaseError(
+        DatabaseErrorKind::Unknown,
+        Box::new(FakeInfo("deadlock detected"))
+    )));
+    assert!(!is_retryable_error(&DieselError::DatabaseError(
+        DatabaseErrorKind::Unknown,
+        Box::new(FakeInfo("some other error"))
+    )));
+    assert!(!is_retryable_error(&DieselError::NotFound));
+}